@@ -0,0 +1,80 @@
+/// Tiny on-disk cache of the last successfully-reconciled record set, in the
+/// spirit of mdns-sd's "always resolve from the cache" refactor. Lets a steady
+/// state skip the Dreamhost `list` round-trip entirely: we only fall back to
+/// the API when the freshly discovered home IPs differ from the cache, the
+/// previous pass failed, or a periodic forced relist is due.
+use std::fs;
+use std::io::{Result, Error, ErrorKind, Write};
+use serde_json::Value;
+
+use crate::dreamhost::Record;
+
+pub struct RecordCache {
+    path: String,
+}
+
+impl RecordCache {
+    pub fn new(path: String) -> Self {
+        RecordCache { path }
+    }
+
+    /// Load the cached record set, or None if the file is absent or unreadable.
+    pub fn load(&self) -> Option<Vec<Record>> {
+        let contents = fs::read_to_string(&self.path).ok()?;
+        let value: Value = serde_json::from_str(&contents).ok()?;
+        match value {
+            Value::Array(entries) => Some(entries.iter().filter_map(Record::from_json).collect()),
+            _ => None,
+        }
+    }
+
+    /// Persist the record set atomically by writing a sibling temp file and
+    /// renaming it over the target, so a crash mid-write can't leave a torn
+    /// cache behind.
+    pub fn store(&self, records: &[Record]) -> Result<()> {
+        let entries: Vec<Value> = records.iter().map(Record::to_json).collect();
+        let serialized = serde_json::to_string(&Value::Array(entries))?;
+
+        let tmp = format!("{}.tmp", self.path);
+        {
+            let mut f = fs::File::create(&tmp)?;
+            f.write_all(serialized.as_bytes())?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)
+            .map_err(|e| Error::new(ErrorKind::Other,
+                format!("Couldn't replace cache file {}: {}", self.path, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    #[test]
+    fn round_trips_a_record_set() {
+        let path = std::env::temp_dir()
+            .join("dreamhost-ddns-test-cache.json")
+            .to_str().unwrap().to_string();
+        let cache = RecordCache::new(path.clone());
+
+        let records = vec![
+            Record::new(&IpAddr::from_str("203.0.113.5").unwrap()),
+            Record::new(&IpAddr::from_str("2001:db8::1").unwrap()),
+        ];
+        cache.store(&records).unwrap();
+
+        let loaded = cache.load().expect("cache should load what was just stored");
+        assert!(loaded == records);
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn missing_cache_loads_as_none() {
+        let cache = RecordCache::new("/nonexistent/dreamhost-ddns.cache".to_string());
+        assert!(cache.load().is_none());
+    }
+}
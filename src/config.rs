@@ -0,0 +1,115 @@
+/// Optional JSON config file for managing several DDNS hostnames from a single
+/// daemon, so the external IP is resolved once per pass instead of once per
+/// process. Shaped after the hosts list in projects like Alfis: a single API
+/// key, an optional shared IP-source/quorum override, and a list of hostnames
+/// each with its own address-family strategy.
+use std::fs;
+use std::io::{Result, Error, ErrorKind};
+use std::net::IpAddr;
+use std::str::FromStr;
+use serde_json::Value;
+
+use crate::ip_resolver::{IpStrategy, IpSourceSpec};
+
+/// A single managed hostname, the families it should own, and any per-host
+/// IP-discovery override. `sources` empty means inherit the shared source list.
+pub struct HostEntry {
+    pub hostname: String,
+    pub strategy: IpStrategy,
+    /// Per-host IP-source override (from `ip_sources` and/or `nameservers`).
+    pub sources: Vec<IpSourceSpec>,
+    /// Per-host quorum; 0 means a simple majority.
+    pub quorum: usize,
+}
+
+pub struct Config {
+    pub key: String,
+    pub hosts: Vec<HostEntry>,
+    /// Shared IP-discovery sources; empty means fall back to the defaults.
+    pub sources: Vec<IpSourceSpec>,
+    /// Shared quorum threshold; 0 means a simple majority.
+    pub quorum: usize,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let root: Value = serde_json::from_str(&contents)
+            .map_err(|e| Error::new(ErrorKind::InvalidData,
+                format!("Couldn't parse config file {}: {}", path, e)))?;
+
+        let key = root["key"].as_str()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Config is missing a string 'key'"))?
+            .to_string();
+
+        let host_entries = match &root["hosts"] {
+            Value::Array(entries) => entries,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Config 'hosts' must be an array")),
+        };
+
+        let mut hosts = Vec::new();
+        for entry in host_entries {
+            let hostname = entry["hostname"].as_str()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData,
+                    "Each host entry needs a string 'hostname'"))?
+                .to_string();
+
+            /* Per-host strategy, defaulting to managing both families. */
+            let strategy = match entry["ip_strategy"].as_str() {
+                Some(s) => IpStrategy::from_str(s)?,
+                None => IpStrategy::Ipv4AndIpv6,
+            };
+
+            /* Optional per-host source override. An explicit `nameservers`
+             * list becomes a dedicated DNS source; an `ip_sources` list picks
+             * named/HTTP backends. Omitting both leaves `sources` empty and the
+             * host inherits the shared source list. */
+            let sources = parse_sources(entry)?;
+            let quorum = entry["quorum"].as_u64().unwrap_or(0) as usize;
+
+            hosts.push(HostEntry { hostname, strategy, sources, quorum });
+        }
+
+        if hosts.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "Config lists no hosts"));
+        }
+
+        /* Optional shared IP-source overrides. */
+        let sources = parse_sources(&root)?;
+        let quorum = root["quorum"].as_u64().unwrap_or(0) as usize;
+
+        Ok(Config { key, hosts, sources, quorum })
+    }
+}
+
+/// Read the IP-source override from a config node: an `ip_sources` array of
+/// source specs plus an optional `nameservers` array that is collapsed into a
+/// single explicit-nameserver DNS source. Shared by the top-level config and
+/// each per-host entry.
+fn parse_sources(node: &Value) -> Result<Vec<IpSourceSpec>> {
+    let mut sources = Vec::new();
+
+    if let Value::Array(list) = &node["ip_sources"] {
+        for s in list {
+            if let Some(spec) = s.as_str() {
+                sources.push(IpSourceSpec::from_str(spec)?);
+            }
+        }
+    }
+
+    if let Value::Array(list) = &node["nameservers"] {
+        let mut servers = Vec::new();
+        for s in list {
+            let addr = s.as_str()
+                .and_then(|s| IpAddr::from_str(s).ok())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData,
+                    "Each 'nameservers' entry must be a string IP address"))?;
+            servers.push(addr);
+        }
+        if !servers.is_empty() {
+            sources.push(IpSourceSpec::Dns(servers));
+        }
+    }
+
+    Ok(sources)
+}
@@ -14,8 +14,9 @@ use std::fmt::{Display, Formatter};
 use serde_json::Value;
 use std::net::IpAddr;
 use std::str::FromStr;
+use crate::retry::RetryConfig;
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Clone)]
 enum RecordKind {
     A,
     AAAA,
@@ -47,6 +48,7 @@ impl FromStr for RecordKind {
     }
 }
 
+#[derive(Clone)]
 pub struct Record {
     /// Parsed value
     pub value: IpAddr,
@@ -54,6 +56,9 @@ pub struct Record {
     r_type: RecordKind,
     /// Annoyingly, dreamhost can't match abbreviated ipv6. It has to be string-for-string match to delete.
     svalue: String,
+    /// TTL as reported by the list API, when present. Locally-built records
+    /// (ones we intend to add) don't carry one.
+    pub ttl: Option<u32>,
 }
 
 impl Record {
@@ -67,8 +72,28 @@ impl Record {
             value: *value,
             r_type,
             svalue: String::new(),
+            ttl: None,
         }
     }
+
+    /// Serialize the identifying fields (type, value, svalue) for the on-disk
+    /// reconciliation cache.
+    pub(crate) fn to_json(&self) -> Value {
+        let mut m = serde_json::Map::new();
+        m.insert("type".to_string(), Value::String(self.r_type.to_string()));
+        m.insert("value".to_string(), Value::String(self.value.to_string()));
+        m.insert("svalue".to_string(), Value::String(self.svalue.clone()));
+        Value::Object(m)
+    }
+
+    /// Rebuild a cached record, returning None if any field is missing or
+    /// malformed so a stale/corrupt cache is simply ignored.
+    pub(crate) fn from_json(v: &Value) -> Option<Self> {
+        let r_type = RecordKind::from_str(v["type"].as_str()?).ok()?;
+        let value = IpAddr::from_str(v["value"].as_str()?).ok()?;
+        let svalue = v["svalue"].as_str()?.to_string();
+        Some(Record { value, r_type, svalue, ttl: None })
+    }
 }
 
 impl std::cmp::PartialEq for Record {
@@ -83,10 +108,12 @@ pub struct Dreamhost {
     easy: Easy,
     key: String,
     ddns_host: String,
+    retry: RetryConfig,
 }
 
 impl Dreamhost {
-    fn execute(&mut self) -> Result<Value> {
+    /// Perform the currently-configured request exactly once.
+    fn execute_once(&mut self) -> Result<Value> {
         let mut buf = Vec::new();
         {
             let mut transfer = self.easy.transfer();
@@ -107,15 +134,27 @@ impl Dreamhost {
         Ok(serde_json::from_str(data)?)
     }
 
-    pub fn new(key: String, ddns_host: String) -> Result<Self> {
+    /// Perform the request, retransmitting transient failures with backoff.
+    fn execute(&mut self) -> Result<Value> {
+        let retry = self.retry;
+        retry.run(|| self.execute_once())
+    }
+
+    pub fn new(key: String, ddns_host: String, retry: RetryConfig) -> Result<Self> {
 
         Ok(Dreamhost {
             easy: Easy::new(),
             key,
             ddns_host,
+            retry,
         })
     }
 
+    /// The DDNS hostname this client manages, for log messages.
+    pub fn hostname(&self) -> &str {
+        &self.ddns_host
+    }
+
     /// Adds a record to the dreamhost API.
     pub fn add(&mut self, r: &Record) -> Result<()> {
         self.easy.url(&format!("{}://{}/?cmd={}&key={}&type={}&value={}&record={}&format=json",
@@ -225,10 +264,17 @@ impl Dreamhost {
                 },
             };
 
+            /* Dreamhost exposes the TTL as either a number or a string, depending
+             * on the record; accept whatever parses and ignore the rest. */
+            let ttl = entry["ttl"].as_u64()
+                .or_else(|| entry["ttl"].as_str().and_then(|s| s.parse().ok()))
+                .map(|t| t as u32);
+
             Some(Record {
                 value,
                 r_type,
                 svalue: String::from(svalue),
+                ttl,
             })
         }).collect::<Vec<Record>>())
     }
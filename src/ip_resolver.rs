@@ -1,42 +1,525 @@
 /// Wrapper to hide the implementation details of external ip address lookup.
-/// Could easily be replaced by an http call to a service like icanhazip.com or 
-/// whatismyipaddress.com.
 ///
-/// Using opendns.com's myip subdomain just lets us skip the dns step of the
-/// http call, which is a meaningless optimization given how infrequently this should
-/// be used.
+/// Discovery is pluggable behind the `IpSource` trait: the original OpenDNS
+/// DNS lookup, a plain HTTP provider (e.g. icanhazip.com), and a variant that
+/// reads the host's own nameservers from `/etc/resolv.conf`. `IpResolver`
+/// drives one or more of these and only accepts an address a quorum of them
+/// agree on, so a single misbehaving or hijacked source can't push a bad
+/// update to Dreamhost.
 use trust_dns_resolver::Resolver;
 use trust_dns_resolver::config::*;
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::io::Result;
+use std::io::{Result, Error, ErrorKind};
+use std::str::FromStr;
+use std::time::Duration;
 use std::vec::Vec;
+use curl::easy::Easy;
+use crate::retry::RetryConfig;
 
-pub struct IpResolver {
+/// Which address families the daemon should manage, borrowed from the
+/// resolver's own `LookupIpStrategy`. Single-stack hosts can pin this to
+/// exactly one record kind so a transient or ULA address of the other
+/// family is never queried, listed, added, or removed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IpStrategy {
+    Ipv4Only,
+    Ipv6Only,
+    Ipv4AndIpv6,
+}
+
+impl IpStrategy {
+    /// True if an address of this family is managed under the strategy.
+    pub fn manages(&self, addr: &IpAddr) -> bool {
+        match self {
+            IpStrategy::Ipv4Only => addr.is_ipv4(),
+            IpStrategy::Ipv6Only => addr.is_ipv6(),
+            IpStrategy::Ipv4AndIpv6 => true,
+        }
+    }
+
+    /// The narrowest strategy that covers the families of both strategies, used to pick the
+    /// query families for the shared resolver when several hosts each manage a
+    /// different family.
+    pub fn union(self, other: IpStrategy) -> IpStrategy {
+        match (self, other) {
+            (IpStrategy::Ipv4Only, IpStrategy::Ipv4Only) => IpStrategy::Ipv4Only,
+            (IpStrategy::Ipv6Only, IpStrategy::Ipv6Only) => IpStrategy::Ipv6Only,
+            _ => IpStrategy::Ipv4AndIpv6,
+        }
+    }
+
+    fn lookup_strategy(&self) -> LookupIpStrategy {
+        match self {
+            IpStrategy::Ipv4Only => LookupIpStrategy::Ipv4Only,
+            IpStrategy::Ipv6Only => LookupIpStrategy::Ipv6Only,
+            IpStrategy::Ipv4AndIpv6 => LookupIpStrategy::Ipv4AndIpv6,
+        }
+    }
+}
+
+impl FromStr for IpStrategy {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Error> {
+        match s {
+            "ipv4" | "Ipv4Only" => Ok(Self::Ipv4Only),
+            "ipv6" | "Ipv6Only" => Ok(Self::Ipv6Only),
+            "both" | "Ipv4AndIpv6" => Ok(Self::Ipv4AndIpv6),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unmatched IpStrategy")),
+        }
+    }
+}
+
+/// The default OpenDNS nameservers this daemon has always queried.
+fn opendns_nameservers() -> NameServerConfigGroup {
+    NameServerConfigGroup::from_ips_clear(&[
+            IpAddr::V4(Ipv4Addr::new(208, 67, 222, 222)),
+            IpAddr::V4(Ipv4Addr::new(208, 67, 220, 220)),
+            IpAddr::V6(Ipv6Addr::new(2620, 119, 35, 0, 0, 0, 0, 35)),
+            IpAddr::V6(Ipv6Addr::new(2620, 119, 53, 0, 0, 0, 0, 53)),
+        ], 53)
+}
+
+/// The outcome of a discovery query: the observed addresses plus the shortest
+/// record TTL the backend saw, if it has one (HTTP providers don't).
+///
+/// For the aggregated result `IpResolver` hands back, `managed_v4`/`managed_v6`
+/// record whether each family actually reached consensus this pass. A family
+/// that didn't is left for the caller to skip entirely — neither trusting an
+/// unverified address nor deleting the records already in place — so a lapse in
+/// quorum can never turn into a bad write or an accidental removal.
+pub struct Lookup {
+    pub addrs: Vec<IpAddr>,
+    pub ttl: Option<u32>,
+    managed_v4: bool,
+    managed_v6: bool,
+}
+
+impl Lookup {
+    /// A raw single-source observation. Its per-family `managed` flags are
+    /// irrelevant — only the aggregated result's flags are consulted — so they
+    /// default to true.
+    pub fn observed(addrs: Vec<IpAddr>, ttl: Option<u32>) -> Self {
+        Lookup { addrs, ttl, managed_v4: true, managed_v6: true }
+    }
+
+    /// Whether this address's family reached consensus this pass and should be
+    /// reconciled. Records of an unmanaged family are left untouched.
+    pub fn manages(&self, addr: &IpAddr) -> bool {
+        if addr.is_ipv4() { self.managed_v4 } else { self.managed_v6 }
+    }
+}
+
+/// A single external-IP discovery backend. Implementors return every address
+/// they observe for this host of the families `strategy` manages; quorum is
+/// applied above.
+pub trait IpSource {
+    fn lookup_ips(&self, strategy: IpStrategy) -> Result<Lookup>;
+}
+
+/// Seconds remaining on the shortest record in a resolver response.
+fn min_record_ttl(lookup: &trust_dns_resolver::lookup_ip::LookupIp) -> Option<u32> {
+    lookup.as_lookup().record_iter().map(|r| r.ttl()).min()
+}
+
+/// A resolver-backed discovery source: the `myip.opendns.com` query this
+/// daemon has always used, aimed at a configurable nameserver set. The three
+/// constructors select the servers — OpenDNS's own, the host's
+/// `/etc/resolv.conf`, or an explicit list — but the query itself is identical,
+/// so they share one `IpSource` impl rather than three copies of it.
+pub struct ResolverSource {
     resolver: Resolver,
 }
 
-impl IpResolver {
-    pub fn new() -> Result<Self> {
-
-        let ns = NameServerConfigGroup::from_ips_clear(&[
-                IpAddr::V4(Ipv4Addr::new(208, 67, 222, 222)),
-                IpAddr::V4(Ipv4Addr::new(208, 67, 220, 220)),
-                IpAddr::V6(Ipv6Addr::new(2620, 119, 35, 0, 0, 0, 0, 35)),
-                IpAddr::V6(Ipv6Addr::new(2620, 119, 53, 0, 0, 0, 0, 53)),
-            ], 53);
+impl ResolverSource {
+    fn build(ns: NameServerConfigGroup, mut opts: ResolverOpts, strategy: IpStrategy) -> Result<Self> {
+        opts.ip_strategy = strategy.lookup_strategy();
         let config = ResolverConfig::from_parts(None, vec![], ns);
-        let resolver = Resolver::new(config, ResolverOpts::default())?;
+        Ok(ResolverSource { resolver: Resolver::new(config, opts)? })
+    }
+
+    /// OpenDNS over its fixed nameserver set — the daemon's original lookup,
+    /// which avoids the DNS step an HTTP provider would need.
+    pub fn from_opendns(strategy: IpStrategy) -> Result<Self> {
+        Self::build(opendns_nameservers(), ResolverOpts::default(), strategy)
+    }
+
+    /// The same query aimed at the nameservers the host has configured in
+    /// `/etc/resolv.conf`, falling back to the OpenDNS defaults when the file
+    /// is absent or lists no servers.
+    pub fn from_system(strategy: IpStrategy) -> Result<Self> {
+        let (ns, opts) = parse_resolv_conf("/etc/resolv.conf");
+        Self::build(ns, opts, strategy)
+    }
+
+    /// The same query aimed at an explicit nameserver set, used for a config
+    /// entry's per-host `nameservers` override.
+    pub fn from_nameservers(nameservers: &[IpAddr], strategy: IpStrategy) -> Result<Self> {
+        Self::build(NameServerConfigGroup::from_ips_clear(nameservers, 53), ResolverOpts::default(), strategy)
+    }
+}
+
+impl IpSource for ResolverSource {
+    fn lookup_ips(&self, strategy: IpStrategy) -> Result<Lookup> {
+        let dns_response = self.resolver.lookup_ip("myip.opendns.com")?;
+        let ttl = min_record_ttl(&dns_response);
+        let addrs = dns_response.iter().filter(|ip| strategy.manages(ip)).collect();
+        Ok(Lookup::observed(addrs, ttl))
+    }
+}
+
+/// Parse `nameserver` lines and a couple of `options` (`ndots`, `timeout`,
+/// `attempts`) out of a resolv.conf-style file, as mtop's `resolv.rs` does.
+/// Returns the OpenDNS defaults when no file or no nameservers are found.
+fn parse_resolv_conf(path: &str) -> (NameServerConfigGroup, ResolverOpts) {
+    let mut opts = ResolverOpts::default();
+    let contents = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(_) => return (opendns_nameservers(), opts),
+    };
+
+    let mut servers: Vec<IpAddr> = Vec::new();
+    for line in contents.lines() {
+        let line = match line.split('#').next() {
+            Some(l) => l.trim(),
+            None => continue,
+        };
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("nameserver") => {
+                if let Some(addr) = fields.next().and_then(|s| IpAddr::from_str(s).ok()) {
+                    servers.push(addr);
+                }
+            }
+            Some("options") => {
+                for opt in fields {
+                    let mut kv = opt.splitn(2, ':');
+                    match (kv.next(), kv.next()) {
+                        (Some("ndots"), Some(v)) => {
+                            if let Ok(n) = v.parse() { opts.ndots = n; }
+                        }
+                        (Some("timeout"), Some(v)) => {
+                            if let Ok(n) = v.parse() { opts.timeout = Duration::from_secs(n); }
+                        }
+                        (Some("attempts"), Some(v)) => {
+                            if let Ok(n) = v.parse() { opts.attempts = n; }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if servers.is_empty() {
+        (opendns_nameservers(), opts)
+    } else {
+        (NameServerConfigGroup::from_ips_clear(&servers, 53), opts)
+    }
+}
+
+/// Fetches our external IP as a bare string from an HTTP provider such as
+/// `https://icanhazip.com`. The body is expected to be a single IP address.
+pub struct HttpProvider {
+    url: String,
+}
+
+impl HttpProvider {
+    pub fn new(url: String) -> Self {
+        HttpProvider { url }
+    }
+}
+
+impl IpSource for HttpProvider {
+    fn lookup_ips(&self, strategy: IpStrategy) -> Result<Lookup> {
+        let mut easy = Easy::new();
+        easy.url(&self.url)?;
+        let mut buf = Vec::new();
+        {
+            let mut transfer = easy.transfer();
+            transfer.write_function(|new_data| {
+                buf.extend_from_slice(new_data);
+                Ok(new_data.len())
+            })?;
+            transfer.perform()?;
+        }
+
+        let body = match std::str::from_utf8(&buf) {
+            Ok(s) => s,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e.to_string())),
+        };
+
+        let addr = IpAddr::from_str(body.trim())
+            .map_err(|e| Error::new(ErrorKind::InvalidData,
+                format!("HTTP provider {} returned a non-IP body: {}", self.url, e)))?;
+
+        let addrs = if strategy.manages(&addr) { vec![addr] } else { vec![] };
+        Ok(Lookup::observed(addrs, None))
+    }
+}
+
+/// Specifies a single discovery backend on the command line. `opendns` and
+/// `system` select the DNS sources; anything containing `://` is treated as
+/// an HTTP provider URL.
+pub enum IpSourceSpec {
+    OpenDns,
+    System,
+    Http(String),
+    /// An explicit nameserver set, built from a config entry's `nameservers`
+    /// override. Not reachable from the CLI `FromStr` (a list can't be given in
+    /// a single token); the config parser constructs it directly.
+    Dns(Vec<IpAddr>),
+}
+
+impl FromStr for IpSourceSpec {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Error> {
+        match s {
+            "opendns" => Ok(Self::OpenDns),
+            "system" => Ok(Self::System),
+            url if url.contains("://") => Ok(Self::Http(url.to_string())),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unmatched IpSourceSpec")),
+        }
+    }
+}
+
+/// Drives one or more `IpSource`s and returns only the addresses a quorum of
+/// them agree on.
+pub struct IpResolver {
+    sources: Vec<Box<dyn IpSource>>,
+    quorum: usize,
+    strategy: IpStrategy,
+    retry: RetryConfig,
+}
+
+impl IpResolver {
+    /// Build a resolver from a list of source specs and a quorum threshold.
+    /// An empty spec list defaults to a single OpenDNS source; a quorum of 0
+    /// defaults to a simple majority. The threshold is stored raw and resolved
+    /// per address family at lookup time against the *configured* source count
+    /// for that family (see `family_quorum`), not the number that happened to
+    /// respond — so degrading the honest sources can't shrink the denominator
+    /// until a lone survivor clears the bar.
+    pub fn new(specs: &[IpSourceSpec], quorum: usize, strategy: IpStrategy, retry: RetryConfig) -> Result<Self> {
+        let mut sources: Vec<Box<dyn IpSource>> = Vec::new();
+        if specs.is_empty() {
+            sources.push(Box::new(ResolverSource::from_opendns(strategy)?));
+        } else {
+            for spec in specs {
+                match spec {
+                    IpSourceSpec::OpenDns => sources.push(Box::new(ResolverSource::from_opendns(strategy)?)),
+                    IpSourceSpec::System => sources.push(Box::new(ResolverSource::from_system(strategy)?)),
+                    IpSourceSpec::Http(url) => sources.push(Box::new(HttpProvider::new(url.clone()))),
+                    IpSourceSpec::Dns(ns) => sources.push(Box::new(ResolverSource::from_nameservers(ns, strategy)?)),
+                }
+            }
+        }
 
         Ok(IpResolver {
-            resolver
+            sources,
+            quorum,
+            strategy,
+            retry,
         })
     }
 
-    /// Returns a vec of external IpAddr for this service
-    pub fn lookup_ips(&self) -> Result<Vec<IpAddr>>
-    {
-        /* Ask a DNS service for our IP address */
-        let dns_response = self.resolver.lookup_ip("myip.opendns.com")?;
-        Ok(dns_response.iter().collect())
+    /// Resolve the vote threshold for a family given how many configured sources
+    /// can return it. A configured quorum of 0 means a simple majority of those
+    /// sources; an explicit quorum is honoured verbatim and never clamped down,
+    /// so if it can't be met the family simply goes unconfirmed rather than
+    /// falling back to trusting fewer sources than the operator asked for.
+    fn family_quorum(&self, capable: usize) -> usize {
+        if self.quorum == 0 {
+            capable / 2 + 1
+        } else {
+            self.quorum
+        }
+    }
+
+    /// Query every source and return, per family, the addresses a quorum agree
+    /// on plus whether that family reached consensus at all, along with the
+    /// shortest TTL any source observed. Source failures are logged and simply
+    /// don't contribute a vote. Every source is queried with the resolver's own
+    /// strategy and counted as a voter for each managed family (an HTTP
+    /// provider, which returns a single address, can only vote in one), so the
+    /// denominator is the configured source count — a failing source lowers the
+    /// votes cast, never the bar they must clear.
+    pub fn lookup_ips(&self) -> Result<Lookup> {
+        let n = self.sources.len();
+        let v4_capable = if matches!(self.strategy, IpStrategy::Ipv6Only) { 0 } else { n };
+        let v6_capable = if matches!(self.strategy, IpStrategy::Ipv4Only) { 0 } else { n };
+        let v4_quorum = self.family_quorum(v4_capable);
+        let v6_quorum = self.family_quorum(v6_capable);
+
+        let mut votes: HashMap<IpAddr, usize> = HashMap::new();
+        let mut v4_observers = 0usize;
+        let mut v6_observers = 0usize;
+        let mut ttl: Option<u32> = None;
+        for source in &self.sources {
+            match self.retry.run(|| source.lookup_ips(self.strategy)) {
+                Ok(lookup) => {
+                    if let Some(t) = lookup.ttl {
+                        ttl = Some(ttl.map_or(t, |cur| cur.min(t)));
+                    }
+                    /* Dedupe within a source so one backend can't outvote the
+                     * quorum, and note which families this source contributed to
+                     * so we can tell whether each family reached consensus. */
+                    let mut seen = Vec::new();
+                    let (mut saw_v4, mut saw_v6) = (false, false);
+                    for ip in lookup.addrs {
+                        if !seen.contains(&ip) {
+                            seen.push(ip);
+                            *votes.entry(ip).or_insert(0) += 1;
+                            if ip.is_ipv4() { saw_v4 = true; } else { saw_v6 = true; }
+                        }
+                    }
+                    if saw_v4 { v4_observers += 1; }
+                    if saw_v6 { v6_observers += 1; }
+                }
+                Err(e) => warn!("IP source failed, ignoring its vote: {}", e),
+            }
+        }
+
+        /* A family is managed this pass only when enough sources actually
+         * reported an address of it; otherwise the caller leaves its records
+         * alone rather than deleting records it can no longer confirm. */
+        let managed_v4 = v4_capable > 0 && v4_observers >= v4_quorum;
+        let managed_v6 = v6_capable > 0 && v6_observers >= v6_quorum;
+
+        let addrs = votes.into_iter()
+            .filter(|(ip, count)| {
+                *count >= if ip.is_ipv4() { v4_quorum } else { v6_quorum }
+            })
+            .map(|(ip, _)| ip)
+            .collect();
+        Ok(Lookup { addrs, ttl, managed_v4, managed_v6 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A canned source that reports a fixed address list, for quorum tests.
+    struct StaticSource(Vec<IpAddr>);
+
+    impl IpSource for StaticSource {
+        fn lookup_ips(&self, strategy: IpStrategy) -> Result<Lookup> {
+            let addrs = self.0.iter().copied().filter(|ip| strategy.manages(ip)).collect();
+            Ok(Lookup::observed(addrs, None))
+        }
+    }
+
+    /// A source that always errors, standing in for a down or hijacked-then-
+    /// unreachable backend.
+    struct FailingSource;
+
+    impl IpSource for FailingSource {
+        fn lookup_ips(&self, _strategy: IpStrategy) -> Result<Lookup> {
+            Err(Error::new(ErrorKind::Other, "source down"))
+        }
+    }
+
+    fn resolver(sources: Vec<Box<dyn IpSource>>, quorum: usize) -> IpResolver {
+        IpResolver {
+            sources,
+            quorum,
+            strategy: IpStrategy::Ipv4AndIpv6,
+            retry: RetryConfig::new(0, 0, 1),
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr { IpAddr::from_str(s).unwrap() }
+
+    #[test]
+    fn minority_family_is_left_unmanaged_not_dropped() {
+        /* A dual-stack resolver sees both families; a second source only ever
+         * reports v4. v4 clears quorum and is managed; v6 falls short but is
+         * reported as *unmanaged* rather than accepted-empty, so the caller
+         * leaves the existing AAAA alone instead of deleting it. */
+        let v4addr = ip("203.0.113.5");
+        let v6addr = ip("2001:db8::1");
+        let r = resolver(vec![
+            Box::new(StaticSource(vec![v4addr, v6addr])),
+            Box::new(StaticSource(vec![v4addr])),
+        ], 0);
+
+        let lookup = r.lookup_ips().unwrap();
+        assert_eq!(lookup.addrs, vec![v4addr]);
+        assert!(lookup.manages(&v4addr));
+        assert!(!lookup.manages(&v6addr));
+    }
+
+    #[test]
+    fn disagreeing_source_is_outvoted() {
+        /* Two sources agree on one v4 address; a third reports a different one
+         * that never reaches majority and is rejected. */
+        let good = ip("203.0.113.5");
+        let bad = ip("198.51.100.9");
+        let r = resolver(vec![
+            Box::new(StaticSource(vec![good])),
+            Box::new(StaticSource(vec![good])),
+            Box::new(StaticSource(vec![bad])),
+        ], 0);
+
+        assert_eq!(r.lookup_ips().unwrap().addrs, vec![good]);
+    }
+
+    #[test]
+    fn lone_survivor_cannot_meet_majority_when_others_are_down() {
+        /* Three sources configured, two down, the survivor reporting a bogus
+         * address. The denominator stays at three, so majority is two and the
+         * single vote is neither accepted nor confirmed — an attacker who
+         * degrades the honest sources still can't push an update. */
+        let bad = ip("198.51.100.9");
+        let r = resolver(vec![
+            Box::new(FailingSource),
+            Box::new(FailingSource),
+            Box::new(StaticSource(vec![bad])),
+        ], 0);
+
+        let lookup = r.lookup_ips().unwrap();
+        assert!(lookup.addrs.is_empty());
+        assert!(!lookup.manages(&bad));
+    }
+
+    #[test]
+    fn explicit_quorum_is_not_lowered_by_failures() {
+        /* With an explicit quorum of two and two of three sources down, the
+         * threshold stays at two rather than collapsing to the one survivor. */
+        let bad = ip("198.51.100.9");
+        let r = resolver(vec![
+            Box::new(FailingSource),
+            Box::new(FailingSource),
+            Box::new(StaticSource(vec![bad])),
+        ], 2);
+
+        let lookup = r.lookup_ips().unwrap();
+        assert!(lookup.addrs.is_empty());
+        assert!(!lookup.manages(&bad));
+    }
+
+    #[test]
+    fn resolv_conf_missing_falls_back_to_opendns_defaults() {
+        let (ns, _) = parse_resolv_conf("/nonexistent/resolv.conf");
+        assert_eq!(ns, opendns_nameservers());
+    }
+
+    #[test]
+    fn resolv_conf_parses_nameservers_and_options() {
+        let dir = std::env::temp_dir();
+        let path = dir.join("dreamhost-ddns-test-resolv.conf");
+        std::fs::write(&path,
+            "# a comment\nnameserver 1.1.1.1\nnameserver 8.8.8.8\noptions ndots:3 attempts:5\n").unwrap();
+
+        let (ns, opts) = parse_resolv_conf(path.to_str().unwrap());
+        let expected = NameServerConfigGroup::from_ips_clear(
+            &[ip("1.1.1.1"), ip("8.8.8.8")], 53);
+        assert_eq!(ns, expected);
+        assert_eq!(opts.ndots, 3);
+        assert_eq!(opts.attempts, 5);
+
+        std::fs::remove_file(&path).ok();
     }
 }
@@ -15,27 +15,47 @@ use libc::getpid;
 use syslog::{Facility, Formatter3164, BasicLogger};
 use log::LevelFilter;
 
+mod retry;
+use retry::RetryConfig;
+
 mod ip_resolver;
 use ip_resolver::IpResolver;
+use ip_resolver::IpStrategy;
+use ip_resolver::IpSourceSpec;
+use ip_resolver::Lookup;
 
 mod dreamhost;
 use dreamhost::Dreamhost;
 use dreamhost::Record;
 
+mod cache;
+use cache::RecordCache;
+
+mod config;
+use config::Config;
+use config::HostEntry;
+
 lazy_static!{
     static ref OPTS: Opts = Opts::parse();
 }
 
 #[derive(Clap)]
 struct Opts {
-    /// Sets the hostname to use for DDNS on Dreamhost.
+    /// Sets the hostname to use for DDNS on Dreamhost. Required unless
+    /// --config is given.
     #[clap(short = "h", long = "hostname")]
-    hostname: String,
+    hostname: Option<String>,
 
-    /// Sets the API Key to use, from dreamhost's webpanel.
+    /// Sets the API Key to use, from dreamhost's webpanel. Required unless
+    /// --config is given.
     /// Visit https://panel.dreamhost.com/?tree=home.api to get one.
     #[clap(short = "k", long = "key")]
-    key: String,
+    key: Option<String>,
+
+    /// Path to a JSON config file describing multiple hostnames to manage from
+    /// one daemon. When given, --hostname and --key are ignored.
+    #[clap(short = "f", long = "config")]
+    config: Option<String>,
 
     /// Verbosity. Only errors are logged by default.
     #[clap(short = "v", long = "verbose", parse(from_occurrences))]
@@ -48,23 +68,99 @@ struct Opts {
     /// Maximum seconds to wati between refreshes.
     #[clap(short = "M", long = "max-sleep", default_value = "1800")]
     max_sleep: u32,
+
+    /// Which address families to manage: "ipv4", "ipv6", or "both".
+    /// Single-stack hosts can pin this so records of the other family are
+    /// never listed, added, or removed.
+    #[clap(short = "s", long = "ip-strategy", default_value = "both")]
+    ip_strategy: IpStrategy,
+
+    /// IP-discovery source(s) to consult. Repeatable; each is "opendns",
+    /// "system" (reads /etc/resolv.conf), or an HTTP provider URL such as
+    /// "https://icanhazip.com". Defaults to a single OpenDNS lookup.
+    #[clap(short = "S", long = "ip-source")]
+    ip_sources: Vec<IpSourceSpec>,
+
+    /// Minimum number of sources that must agree on an address before it is
+    /// accepted. 0 (the default) means a simple majority of the sources.
+    #[clap(short = "q", long = "quorum", default_value = "0")]
+    quorum: usize,
+
+    /// Initial per-request retry delay in milliseconds. Doubles each attempt.
+    #[clap(long = "retry-initial-ms", default_value = "1000")]
+    retry_initial_ms: u64,
+
+    /// Maximum per-request retry delay in milliseconds.
+    #[clap(long = "retry-max-ms", default_value = "10000")]
+    retry_max_ms: u64,
+
+    /// Maximum number of attempts per request before giving up.
+    #[clap(long = "retry-attempts", default_value = "4")]
+    retry_attempts: u32,
+
+    /// Base path of the on-disk reconciliation cache, used to skip redundant
+    /// Dreamhost API calls when nothing has changed since the last pass. Each
+    /// hostname's cache is stored at this path with ".<hostname>" appended.
+    #[clap(short = "c", long = "cache-file", default_value = "/tmp/dreamhost-ddns.cache")]
+    cache_file: String,
 }
 
-fn heartbeat(resolver : &IpResolver, dreamhost: &mut Dreamhost) -> bool {
+impl Opts {
+    fn retry_config(&self) -> RetryConfig {
+        RetryConfig::new(self.retry_initial_ms, self.retry_max_ms, self.retry_attempts)
+    }
+}
 
-    let home_ip_addrs = match resolver.lookup_ips() {
-        Ok(ips) => ips,
-        Err(error) => {
-            error!("Error resolving home IP: {}", error);
-            return false;
-        }
-    };
+/// The outcome of a single heartbeat pass: whether it succeeded and the
+/// shortest record TTL observed this pass (from the resolver and the Dreamhost
+/// list), used to schedule the next pass before those records expire.
+struct Heartbeat {
+    success: bool,
+    ttl: Option<u32>,
+}
+
+impl Heartbeat {
+    fn failed() -> Self {
+        Heartbeat { success: false, ttl: None }
+    }
+}
+
+/// Order-independent equality of two record sets (by type and value).
+fn same_record_set(a: &[Record], b: &[Record]) -> bool {
+    a.len() == b.len() && a.iter().all(|r| b.contains(r))
+}
 
-    let mut home_ips : Vec<Record> = home_ip_addrs.iter().map(Record::new).collect();
+/// Reconcile a single hostname against an already-resolved set of home IPs.
+/// The external IP is resolved once per pass and this runs per managed host, so
+/// `lookup` is shared (unless the host has its own IP sources) and `strategy`
+/// narrows it to the families this host owns.
+fn reconcile(lookup: &Lookup, strategy: IpStrategy, dreamhost: &mut Dreamhost, cache: &RecordCache, force: bool) -> Heartbeat {
+
+    let mut observed_ttl = lookup.ttl;
+    let mut home_ips : Vec<Record> = lookup.addrs.iter()
+        .filter(|ip| strategy.manages(ip) && lookup.manages(ip))
+        .map(Record::new)
+        .collect();
 
     if home_ips.is_empty() {
         error!("Got 0 ip addresses from dns service");
-        return false;
+        return Heartbeat::failed();
+    }
+
+    /* The set we intend to own after this pass. Snapshot it before the
+     * reconciliation loop consumes `home_ips`, so we can refresh the cache. */
+    let reconciled = home_ips.clone();
+
+    /* Fast path: if the previous pass succeeded and the discovered home IPs
+     * still match what we last reconciled, Dreamhost is already correct and we
+     * can skip the list/add/remove round-trips entirely. */
+    if !force {
+        if let Some(cached) = cache.load() {
+            if same_record_set(&cached, &home_ips) {
+                info!("Home IP set unchanged since last reconcile; skipping Dreamhost list.");
+                return Heartbeat { success: true, ttl: observed_ttl };
+            }
+        }
     }
 
     /* Make a request to the dreamhost API */
@@ -72,10 +168,22 @@ fn heartbeat(resolver : &IpResolver, dreamhost: &mut Dreamhost) -> bool {
         Ok(ips) => ips,
         Err(error) => {
             error!("Error querying dreamhost list api: {}", error);
-            return false;
+            return Heartbeat::failed();
         }
     };
 
+    /* Only reconcile records of the families we manage *and* that reached
+     * consensus this pass. A disabled family, or one whose sources couldn't
+     * confirm it, is left untouched so we never churn something we don't own
+     * or can't currently verify. */
+    dh_ips.retain(|dh_ip| strategy.manages(&dh_ip.value) && lookup.manages(&dh_ip.value));
+
+    /* Fold Dreamhost's own TTLs into the observation so the next pass fires
+     * before the shortest relevant record expires. */
+    for ttl in dh_ips.iter().filter_map(|r| r.ttl) {
+        observed_ttl = Some(observed_ttl.map_or(ttl, |cur| cur.min(ttl)));
+    }
+
     /* Dreamhost allows any number of A or AAAA records for ipv4 and ipv6 respectively.
      * First, remove ips from both lists when they match.
      */
@@ -95,17 +203,22 @@ fn heartbeat(resolver : &IpResolver, dreamhost: &mut Dreamhost) -> bool {
     if dh_ips.is_empty() && home_ips.is_empty() {
         // If there was a match for every element in both arrays, we're already up to date.
         info!("Dreamhost was found to be up-to-date.");
-        return true;
+        store_cache(cache, &reconciled);
+        return Heartbeat { success: true, ttl: observed_ttl };
     }
 
     /* Next, delete any records from dreamhost that remain.
      * Any record that matched a home ip was already removed from the array,
      * so dh_ips now contains only records that must be removed.
      */
+    let mut all_removed = true;
     for i in &dh_ips {
         match dreamhost.remove(i) {
             Ok(_) => info!("Removed ip from dreamhost: {}", i.value),
-            Err(e) => error!("Error removing record {}: {}. Continuing.", i.value, e),
+            Err(e) => {
+                error!("Error removing record {}: {}. Continuing.", i.value, e);
+                all_removed = false;
+            }
         }
     }
 
@@ -115,21 +228,52 @@ fn heartbeat(resolver : &IpResolver, dreamhost: &mut Dreamhost) -> bool {
             Ok(_) => info!("Added IP {} to dreamhost dns", i.value),
             Err(e) => {
                 error!("Error adding new IP to dreamhost: {}. Will hopefully be added next pass.", e);
-                return false;
+                return Heartbeat::failed();
             },
         }
     }
-    
-    true
+
+    /* A record we failed to delete would otherwise be masked next pass: the
+     * home-IP set is unchanged, the cache fast-path skips the list, and the
+     * stale record lingers forever. Treat it as a failure so `force` stays set
+     * and we re-list until the repair lands — and don't trust the cache yet. */
+    if !all_removed {
+        return Heartbeat::failed();
+    }
+
+    store_cache(cache, &reconciled);
+    Heartbeat { success: true, ttl: observed_ttl }
 }
 
-fn update_timer(success : bool, last_s : u32) -> u32 {
+/// Update the reconciliation cache, logging (but not failing the pass) if the
+/// write doesn't land — a stale cache only costs us an extra list next pass.
+fn store_cache(cache: &RecordCache, records: &[Record]) {
+    if let Err(e) = cache.store(records) {
+        error!("Couldn't update reconciliation cache: {}", e);
+    }
+}
+
+/// Re-query a little before the shortest observed TTL expires, the same
+/// re-query-before-expiry trick mdns-sd uses to refresh PTR records.
+const REFRESH_FRACTION : f64 = 0.8;
+
+fn update_timer(beat : &Heartbeat, last_s : u32) -> u32 {
     let min_sleep_s : u32 = OPTS.min_sleep;
     let max_sleep_s : u32 = OPTS.max_sleep;
 
-    if success {
-        /* If the heartbeat was successful, reset to regular intervals */
-        return min_sleep_s;
+    if beat.success {
+        /* Schedule the next pass shortly before the shortest relevant TTL
+         * expires, clamped to the configured bounds. Absent any TTL we fall
+         * back to the minimum interval. */
+        return match beat.ttl {
+            Some(ttl) => {
+                let target = (f64::from(ttl) * REFRESH_FRACTION) as u32;
+                /* A misconfigured min > max would make `clamp` panic; keep the
+                 * upper bound authoritative by lowering the floor to meet it. */
+                target.clamp(min_sleep_s.min(max_sleep_s), max_sleep_s)
+            }
+            None => min_sleep_s,
+        };
     }
 
     /* If the last run fails, add a small delay to back-off.
@@ -155,24 +299,135 @@ fn setup_logging() {
         .map(|()| log::set_max_level(level)).unwrap();
 }
 
+/// How many passes to allow after the last successful forced full Dreamhost
+/// `list` before forcing another, so an out-of-band panel edit that the cache can't observe is
+/// eventually detected and repaired rather than masked indefinitely.
+const FORCE_RELIST_EVERY : u32 = 20;
+
+/// One managed hostname: its Dreamhost client, the families it owns, its own
+/// reconciliation cache, whether the previous pass for this host failed, and
+/// how many passes have gone by since the last successful forced full reconcile.
+struct Target {
+    dreamhost: Dreamhost,
+    strategy: IpStrategy,
+    cache: RecordCache,
+    force: bool,
+    passes_since_full: u32,
+    /// A dedicated resolver when this host overrides the shared IP sources;
+    /// `None` means it reuses the once-per-pass shared lookup.
+    resolver: Option<IpResolver>,
+}
+
+/// Derive a per-host cache path so each hostname keeps its own reconciled set.
+fn cache_path_for(hostname: &str) -> String {
+    format!("{}.{}", OPTS.cache_file, hostname.replace('/', "_"))
+}
+
 fn main() {
     setup_logging();
 
-    /* Set up a resolver */
-    let resolver = ip_resolver::IpResolver::new().unwrap();
-    /* Set up access to the dreamhost api */
-    let mut dreamhost = dreamhost::Dreamhost::new(OPTS.key.clone(), OPTS.hostname.clone()).unwrap();
+    let retry = OPTS.retry_config();
+
+    /* Either a config file drives several hostnames, or the single --hostname
+     * CLI path is used for backward compatibility. */
+    let (key, hosts, sources, quorum) = match &OPTS.config {
+        Some(path) => {
+            let config = Config::load(path).expect("Couldn't load config file");
+            (config.key, config.hosts, config.sources, config.quorum)
+        }
+        None => {
+            let hostname = OPTS.hostname.clone().expect("--hostname is required without --config");
+            let key = OPTS.key.clone().expect("--key is required without --config");
+            let entry = HostEntry {
+                hostname,
+                strategy: OPTS.ip_strategy,
+                sources: Vec::new(),
+                quorum: 0,
+            };
+            (key, vec![entry], Vec::new(), OPTS.quorum)
+        }
+    };
+
+    /* Resolve the union of every host's families once per pass; each host then
+     * filters that result down to the families it owns. Querying only the
+     * families some host actually manages keeps a single-stack deployment from
+     * issuing AAAA (or A) lookups it will only discard. */
+    let effective_strategy = hosts.iter()
+        .map(|entry| entry.strategy)
+        .reduce(IpStrategy::union)
+        .unwrap_or(IpStrategy::Ipv4AndIpv6);
+    let resolver_sources = if sources.is_empty() { &OPTS.ip_sources } else { &sources };
+    let resolver = IpResolver::new(resolver_sources, quorum, effective_strategy, retry).unwrap();
+
+    let mut targets : Vec<Target> = hosts.into_iter().map(|entry| {
+        let cache = RecordCache::new(cache_path_for(&entry.hostname));
+        let dreamhost = Dreamhost::new(key.clone(), entry.hostname.clone(), retry).unwrap();
+        /* A host with its own source list gets a dedicated resolver; otherwise
+         * it shares the daemon-wide lookup resolved once per pass. */
+        let resolver = if entry.sources.is_empty() {
+            None
+        } else {
+            Some(IpResolver::new(&entry.sources, entry.quorum, entry.strategy, retry).unwrap())
+        };
+        Target { dreamhost, strategy: entry.strategy, cache, force: false, passes_since_full: 0, resolver }
+    }).collect();
 
-    /* Poll the resolver and update the IP */
+    /* Poll the resolver and update each hostname. */
     let mut sleep_s:u32 = 0;
     loop {
-        /* Try to update dreamhost */
-        let succeeded : bool = heartbeat(&resolver, &mut dreamhost);
+        /* Resolve the shared external IP once per pass, but only if at least one
+         * host actually relies on it (others bring their own resolver). */
+        let shared_lookup = if targets.iter().any(|t| t.resolver.is_none()) {
+            match resolver.lookup_ips() {
+                Ok(lookup) => Some(lookup),
+                Err(error) => {
+                    error!("Error resolving home IP: {}", error);
+                    None
+                }
+            }
+        } else {
+            None
+        };
 
-        /* Determine how long to wait based on whether or not the update succeeded */
-        sleep_s = update_timer(succeeded, sleep_s);
+        /* Reconcile every host independently and fold their results together:
+         * the pass only counts as a success when every host succeeded, but one
+         * failing host never stops the others from updating. */
+        let mut all_ok = true;
+        let mut observed_ttl : Option<u32> = None;
+        for target in targets.iter_mut() {
+            /* Force a full list after a failure or once enough passes have gone
+             * by since the last forced one, so out-of-band drift is eventually caught. */
+            let force = target.force || target.passes_since_full >= FORCE_RELIST_EVERY;
+            /* Use this host's own resolver when it has one, else the shared
+             * lookup. A per-host resolver failure only fails this host. */
+            let own_lookup = target.resolver.as_ref().map(|r| match r.lookup_ips() {
+                Ok(lookup) => Some(lookup),
+                Err(error) => {
+                    error!("Error resolving home IP for {}: {}", target.dreamhost.hostname(), error);
+                    None
+                }
+            });
+            let lookup = match &own_lookup {
+                Some(own) => own.as_ref(),
+                None => shared_lookup.as_ref(),
+            };
+            let beat = match lookup {
+                Some(lookup) => reconcile(lookup, target.strategy, &mut target.dreamhost, &target.cache, force),
+                None => Heartbeat::failed(),
+            };
+            target.force = !beat.success;
+            target.passes_since_full = if force && beat.success { 0 } else { target.passes_since_full.saturating_add(1) };
+            all_ok &= beat.success;
+            if let Some(ttl) = beat.ttl {
+                observed_ttl = Some(observed_ttl.map_or(ttl, |cur| cur.min(ttl)));
+            }
+        }
+
+        /* Determine how long to wait based on the aggregate result and TTLs */
+        let beat = Heartbeat { success: all_ok, ttl: observed_ttl };
+        sleep_s = update_timer(&beat, sleep_s);
 
         /* Delay the subsequent attempt */
         thread::sleep(time::Duration::from_secs(sleep_s.into()));
-    }        
+    }
 }
@@ -0,0 +1,124 @@
+/// Small per-request retransmit helper, modeled on smoltcp's DNS socket
+/// retransmit logic: start with a short delay, double it each attempt up to a
+/// cap, add a little jitter, and give up once the attempt budget or an overall
+/// timeout is exhausted. Lets a single dropped UDP response or transient HTTPS
+/// hiccup self-heal without disturbing the outer heartbeat cadence.
+use std::cmp::min;
+use std::io::Result;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Hard ceiling on how long a single request is allowed to keep retrying.
+const OVERALL_TIMEOUT : Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy)]
+pub struct RetryConfig {
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: u32,
+}
+
+impl RetryConfig {
+    pub fn new(initial_delay_ms: u64, max_delay_ms: u64, max_attempts: u32) -> Self {
+        RetryConfig {
+            initial_delay: Duration::from_millis(initial_delay_ms),
+            max_delay: Duration::from_millis(max_delay_ms),
+            max_attempts: max_attempts.max(1),
+        }
+    }
+
+    /// Run `op`, retrying on error with exponential backoff and jitter until it
+    /// succeeds, the attempt budget is spent, or the overall timeout elapses.
+    pub fn run<T, F>(&self, mut op: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let start = Instant::now();
+        let mut delay = self.initial_delay;
+
+        for attempt in 1..=self.max_attempts {
+            match op() {
+                Ok(v) => return Ok(v),
+                Err(e) => {
+                    let elapsed = start.elapsed();
+                    if attempt == self.max_attempts || elapsed >= OVERALL_TIMEOUT {
+                        return Err(e);
+                    }
+
+                    /* Don't sleep past the overall budget. */
+                    let remaining = OVERALL_TIMEOUT - elapsed;
+                    let wait = min(delay + jitter(delay), remaining);
+                    warn!("Request attempt {} failed ({}); retrying in {:?}.", attempt, e, wait);
+                    thread::sleep(wait);
+
+                    delay = min(delay * 2, self.max_delay);
+                }
+            }
+        }
+
+        unreachable!("retry loop exits via the max-attempts branch");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::{Error, ErrorKind};
+
+    fn err() -> Error {
+        Error::new(ErrorKind::Other, "boom")
+    }
+
+    #[test]
+    fn returns_immediately_on_first_success() {
+        let calls = Cell::new(0);
+        /* Zero delays keep the test from actually sleeping. */
+        let cfg = RetryConfig::new(0, 0, 4);
+        let out: Result<u32> = cfg.run(|| { calls.set(calls.get() + 1); Ok(7) });
+        assert_eq!(out.unwrap(), 7);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn retries_until_it_succeeds() {
+        let calls = Cell::new(0);
+        let cfg = RetryConfig::new(0, 0, 4);
+        let out: Result<u32> = cfg.run(|| {
+            calls.set(calls.get() + 1);
+            if calls.get() < 3 { Err(err()) } else { Ok(42) }
+        });
+        assert_eq!(out.unwrap(), 42);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let calls = Cell::new(0);
+        let cfg = RetryConfig::new(0, 0, 3);
+        let out: Result<u32> = cfg.run(|| { calls.set(calls.get() + 1); Err(err()) });
+        assert!(out.is_err());
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        /* A single attempt never sleeps; this just pins the cap arithmetic. */
+        let cfg = RetryConfig::new(1000, 4000, 1);
+        assert_eq!(cfg.initial_delay, Duration::from_millis(1000));
+        assert_eq!(min(cfg.initial_delay * 2, cfg.max_delay), Duration::from_millis(2000));
+        assert_eq!(min(cfg.initial_delay * 8, cfg.max_delay), cfg.max_delay);
+    }
+}
+
+/// A little randomness (up to ~10% of `delay`) so retries from many daemons
+/// don't stampede in lockstep. We avoid pulling in an rng dependency and seed
+/// off the clock instead.
+fn jitter(delay: Duration) -> Duration {
+    let span = delay.as_millis() as u64 / 10 + 1;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    Duration::from_millis(nanos % span)
+}